@@ -4,7 +4,11 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::collections::hash_map::DefaultHasher;
 use std::fmt;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 
 use clap::ArgMatches;
 use failure_ext::{format_err, Error, Result};
@@ -14,15 +18,16 @@ use std::sync::Arc;
 
 use blobstore::Blobstore;
 use blobstore_factory::{make_blobstore, SqliteFactory, XdbFactory};
+use bytes::BytesMut;
 use cacheblob::{new_memcache_blobstore, CacheBlobstoreExt};
 use censoredblob::{CensoredBlob, SqlCensoredContentStore};
 use cloned::cloned;
 use cmdlib::args;
 use context::CoreContext;
-use futures::future;
+use futures::{future, stream};
 use mercurial_types::{HgChangesetEnvelope, HgFileEnvelope, HgManifestEnvelope};
 use metaconfig_types::{BlobConfig, BlobstoreId, Censoring, MetadataDBConfig, StorageConfig};
-use mononoke_types::{BlobstoreBytes, BlobstoreValue, FileContents, RepositoryId};
+use mononoke_types::{BlobstoreBytes, BlobstoreValue, ContentChunk, FileContents, RepositoryId};
 use prefixblob::PrefixBlobstore;
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
 use slog::{info, warn, Logger};
@@ -62,23 +67,165 @@ fn get_blobstore(
     inner_blobstore_id: Option<u64>,
 ) -> BoxFuture<Arc<dyn Blobstore>, Error> {
     let blobconfig = try_boxfuture!(get_blobconfig(storage_config.blobstore, inner_blobstore_id));
+    make_blobstore_from_dbconfig(repo_id, &storage_config.dbconfig, &blobconfig)
+}
 
-    match storage_config.dbconfig {
+fn make_blobstore_from_dbconfig(
+    repo_id: RepositoryId,
+    dbconfig: &MetadataDBConfig,
+    blobconfig: &BlobConfig,
+) -> BoxFuture<Arc<dyn Blobstore>, Error> {
+    match dbconfig {
         MetadataDBConfig::LocalDB { path } => {
-            make_blobstore(repo_id, &blobconfig, &SqliteFactory::new(path), None)
+            make_blobstore(repo_id, blobconfig, &SqliteFactory::new(path.clone()), None)
         }
         MetadataDBConfig::Mysql {
             db_address,
             sharded_filenodes,
         } => make_blobstore(
             repo_id,
-            &blobconfig,
-            &XdbFactory::new(db_address, None, sharded_filenodes),
+            blobconfig,
+            &XdbFactory::new(db_address.clone(), None, *sharded_filenodes),
             None,
         ),
     }
 }
 
+/// Build one blobstore per entry in a `Multiplexed` storage config's inner
+/// blobstore list, the same way `get_blobstore` builds its single blobstore,
+/// for `--all-inner` consistency auditing.
+fn get_all_inner_blobstores(
+    repo_id: RepositoryId,
+    storage_config: StorageConfig,
+) -> BoxFuture<Vec<(BlobstoreId, Arc<dyn Blobstore>)>, Error> {
+    let dbconfig = storage_config.dbconfig;
+    let blobstores = match storage_config.blobstore {
+        BlobConfig::Multiplexed { blobstores, .. } => blobstores,
+        _ => {
+            return future::err(format_err!(
+                "--all-inner requires a multiplexed blobstore config"
+            ))
+            .boxify();
+        }
+    };
+
+    future::join_all(
+        blobstores
+            .into_iter()
+            .map(move |(blobstore_id, blobconfig)| {
+                make_blobstore_from_dbconfig(repo_id, &dbconfig, &blobconfig)
+                    .map(move |blobstore| (blobstore_id, blobstore))
+            }),
+    )
+    .boxify()
+}
+
+/// A cheap, non-cryptographic hash used only to tell whether two inner
+/// blobstores returned the same bytes for a key.
+fn hash_bytes(bytes: &BlobstoreBytes) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.as_bytes().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Fetch `key` from every inner blobstore of a multiplexed config
+/// concurrently, wrapping each the same way `get_from_sources` does, and
+/// print a per-store table of presence, length, and content hash. Returns
+/// an error -- so the command exits non-zero -- if any store is missing
+/// the key, disagrees on its contents, or failed to answer, and names
+/// which `BlobstoreId`s were at fault.
+fn audit_all_inner(
+    repo_id: RepositoryId,
+    storage_config: StorageConfig,
+    use_memcache: Option<String>,
+    no_prefix: bool,
+    key: String,
+    ctx: CoreContext,
+    maybe_censored_blobs_fut: BoxFuture<Option<HashMap<String, String>>, Error>,
+    scuba_censorship_builder: ScubaSampleBuilder,
+) -> BoxFuture<(), Error> {
+    let error_key = key.clone();
+
+    get_all_inner_blobstores(repo_id, storage_config)
+        .join(maybe_censored_blobs_fut)
+        .and_then(move |(blobstores, maybe_censored_blobs)| {
+            future::join_all(blobstores.into_iter().map(move |(blobstore_id, blobstore)| {
+                cloned!(key, ctx, maybe_censored_blobs, scuba_censorship_builder, use_memcache);
+                get_from_sources(
+                    use_memcache,
+                    blobstore,
+                    no_prefix,
+                    key,
+                    ctx,
+                    maybe_censored_blobs,
+                    scuba_censorship_builder,
+                    repo_id,
+                )
+                .map(|(_blobstore, value)| value)
+                .then(move |res| -> Result<_, Error> { Ok((blobstore_id, res)) })
+            }))
+        })
+        .and_then(move |results| {
+            println!("{:<10} {:<8} {:>10} {:>20}", "store", "present", "bytes", "hash");
+
+            let mut reference_hash = None;
+            let mut missing = Vec::new();
+            let mut divergent = Vec::new();
+            let mut errored = Vec::new();
+
+            for (blobstore_id, result) in &results {
+                match result {
+                    Ok(Some(value)) => {
+                        let hash = hash_bytes(value);
+                        println!(
+                            "{:<10?} {:<8} {:>10} {:>20x}",
+                            blobstore_id,
+                            "yes",
+                            value.as_bytes().len(),
+                            hash
+                        );
+                        match reference_hash {
+                            None => reference_hash = Some(hash),
+                            Some(expected) if expected != hash => {
+                                divergent.push(blobstore_id.clone())
+                            }
+                            _ => {}
+                        }
+                    }
+                    Ok(None) => {
+                        println!("{:<10?} {:<8} {:>10} {:>20}", blobstore_id, "no", "-", "-");
+                        missing.push(blobstore_id.clone());
+                    }
+                    Err(e) => {
+                        println!("{:<10?} {:<8} {:>10} {:>20}", blobstore_id, "error", "-", "-");
+                        errored.push((blobstore_id.clone(), format!("{}", e)));
+                    }
+                }
+            }
+
+            if missing.is_empty() && divergent.is_empty() && errored.is_empty() {
+                Ok(())
+            } else {
+                Err(format_err!(
+                    "inner blobstores disagree on key {:?}: missing={:?} divergent={:?} errored={:?}",
+                    error_key,
+                    missing,
+                    divergent,
+                    errored,
+                ))
+            }
+        })
+        .boxify()
+}
+
+/// Expects `sub_m` to have been built with the following registered on this
+/// subcommand, alongside the existing `inner-blobstore-id`/`decode-as`/
+/// `use-memcache`/`no-prefix`/`KEY` args -- without them, clap rejects the
+/// corresponding flag at parse time before this function ever runs:
+///   - `all-inner`: boolean flag (`Arg::with_name("all-inner").long(..)`)
+///   - `output`: value arg for a file path (`.takes_value(true)`)
+///   - `raw`: boolean flag
+///   - `json`: boolean flag
 pub fn subcommand_blobstore_fetch(
     logger: Logger,
     matches: &ArgMatches<'_>,
@@ -89,7 +236,7 @@ pub fn subcommand_blobstore_fetch(
     let censoring = config.censoring;
     let storage_config = config.storage_config;
     let inner_blobstore_id = args::get_u64_opt(&sub_m, "inner-blobstore-id");
-    let blobstore_fut = get_blobstore(repo_id, storage_config, inner_blobstore_id);
+    let all_inner = sub_m.is_present("all-inner");
 
     let common_config = try_boxfuture!(args::read_common_config(&matches));
     let scuba_censored_table = common_config.scuba_censored_table;
@@ -100,6 +247,9 @@ pub fn subcommand_blobstore_fetch(
     let decode_as = sub_m.value_of("decode-as").map(|val| val.to_string());
     let use_memcache = sub_m.value_of("use-memcache").map(|val| val.to_string());
     let no_prefix = sub_m.is_present("no-prefix");
+    let output_path = sub_m.value_of("output").map(|val| val.to_string());
+    let raw = sub_m.is_present("raw");
+    let json = sub_m.is_present("json");
 
     let maybe_censored_blobs_fut = match censoring {
         Censoring::Enabled => {
@@ -118,6 +268,21 @@ pub fn subcommand_blobstore_fetch(
         Censoring::Disabled => future::ok(None).right_future(),
     };
 
+    if all_inner {
+        return audit_all_inner(
+            repo_id,
+            storage_config,
+            use_memcache,
+            no_prefix,
+            key,
+            ctx,
+            maybe_censored_blobs_fut,
+            scuba_censorship_builder,
+        );
+    }
+
+    let blobstore_fut = get_blobstore(repo_id, storage_config, inner_blobstore_id);
+
     let value_fut = blobstore_fut.join(maybe_censored_blobs_fut).and_then({
         cloned!(logger, key, ctx);
         move |(blobstore, maybe_censored_blobs)| {
@@ -127,43 +292,58 @@ pub fn subcommand_blobstore_fetch(
                 blobstore,
                 no_prefix,
                 key.clone(),
-                ctx,
+                ctx.clone(),
                 maybe_censored_blobs,
                 scuba_censorship_builder,
                 repo_id,
             )
+            .map(move |(blobstore, value)| (blobstore, value, ctx))
         }
     });
 
     value_fut
-        .map({
-            cloned!(key);
-            move |value| {
+        .and_then(move |(blobstore, value, ctx)| -> BoxFuture<(), Error> {
+            if !raw {
                 println!("{:?}", value);
-                if let Some(value) = value {
-                    let decode_as = decode_as.as_ref().and_then(|val| {
-                        let val = val.as_str();
-                        if val == "auto" {
-                            detect_decode(&key, &logger)
-                        } else {
-                            Some(val)
-                        }
-                    });
-
-                    match decode_as {
-                        Some("changeset") => display(&HgChangesetEnvelope::from_blob(value.into())),
-                        Some("manifest") => display(&HgManifestEnvelope::from_blob(value.into())),
-                        Some("file") => display(&HgFileEnvelope::from_blob(value.into())),
-                        // TODO: (rain1) T30974137 add a better way to print out file contents
-                        Some("contents") => println!("{:?}", FileContents::from_blob(value.into())),
-                        _ => (),
-                    }
+            }
+            let value = match value {
+                Some(value) => value,
+                None => return future::ok(()).boxify(),
+            };
+
+            let decode_as = decode_as.as_ref().and_then(|val| {
+                let val = val.as_str();
+                if val == "auto" {
+                    detect_decode(&key, &logger)
+                } else {
+                    Some(val)
                 }
+            });
+
+            match decode_as {
+                Some("changeset") => {
+                    show_envelope(json, &HgChangesetEnvelope::from_blob(value.into()));
+                    future::ok(()).boxify()
+                }
+                Some("manifest") => {
+                    show_envelope(json, &HgManifestEnvelope::from_blob(value.into()));
+                    future::ok(()).boxify()
+                }
+                Some("file") => {
+                    show_envelope(json, &HgFileEnvelope::from_blob(value.into()));
+                    future::ok(()).boxify()
+                }
+                Some("contents") => dump_file_contents(blobstore, ctx, value, output_path, raw),
+                _ => future::ok(()).boxify(),
             }
         })
         .boxify()
 }
 
+/// Fetch `key` through the same prefix/censored/memcache wrapping the
+/// command always applies, also returning that wrapped blobstore (type
+/// erased) so a caller decoding `FileContents` can follow chunk references
+/// with it instead of re-deriving the same wrapping.
 fn get_from_sources<T: Blobstore + Clone>(
     use_memcache: Option<String>,
     blobstore: T,
@@ -173,7 +353,7 @@ fn get_from_sources<T: Blobstore + Clone>(
     censored_blobs: Option<HashMap<String, String>>,
     scuba_censorship_builder: ScubaSampleBuilder,
     repo_id: RepositoryId,
-) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+) -> BoxFuture<(Arc<dyn Blobstore>, Option<BlobstoreBytes>), Error> {
     let empty_prefix = "".to_string();
 
     match use_memcache {
@@ -184,7 +364,10 @@ fn get_from_sources<T: Blobstore + Clone>(
                 true => PrefixBlobstore::new(blobstore, empty_prefix),
             };
             let blobstore = CensoredBlob::new(blobstore, censored_blobs, scuba_censorship_builder);
+            let wrapped: Arc<dyn Blobstore> = Arc::new(blobstore.clone());
             get_cache(ctx.clone(), &blobstore, key.clone(), mode)
+                .map(move |value| (wrapped, value))
+                .boxify()
         }
         None => {
             let blobstore = match no_prefix {
@@ -192,7 +375,11 @@ fn get_from_sources<T: Blobstore + Clone>(
                 true => PrefixBlobstore::new(blobstore, empty_prefix),
             };
             let blobstore = CensoredBlob::new(blobstore, censored_blobs, scuba_censorship_builder);
-            blobstore.get(ctx, key.clone()).boxify()
+            let wrapped: Arc<dyn Blobstore> = Arc::new(blobstore.clone());
+            blobstore
+                .get(ctx, key.clone())
+                .map(move |value| (wrapped, value))
+                .boxify()
         }
     }
 }
@@ -207,6 +394,126 @@ where
     }
 }
 
+/// Like `display`, but emits a single-line JSON object instead of the
+/// `---`-delimited `Display` form, so scripted callers can parse the
+/// result instead of scraping free-form text. Built by hand (rather than
+/// via `Serialize`) since the envelope types only expose `Display`/`Debug`.
+fn display_json<T>(res: &Result<T>)
+where
+    T: fmt::Debug,
+{
+    let (field, value) = match res {
+        Ok(val) => ("ok", format!("{:?}", val)),
+        Err(err) => ("error", format!("{:?}", err)),
+    };
+    println!("{{\"{}\":{}}}", field, json_escape(&value));
+}
+
+fn show_envelope<T>(json: bool, res: &Result<T>)
+where
+    T: fmt::Display + fmt::Debug,
+{
+    if json {
+        display_json(res);
+    } else {
+        display(res);
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Resolve `value` as `FileContents`, following chunk references through
+/// `blobstore` if it's `Chunked` (replacing the old `TODO: (rain1)
+/// T30974137` placeholder that just printed the `Debug` form -- chunked
+/// files never got reassembled and binary data wasn't readable). Gathers
+/// the chunks in memory, then writes the concatenated bytes to
+/// `output_path`, or stdout if not given;
+/// `--raw` suppresses the trailing size summary so the output is exactly
+/// the file's bytes and nothing else.
+fn dump_file_contents(
+    blobstore: Arc<dyn Blobstore>,
+    ctx: CoreContext,
+    value: BlobstoreBytes,
+    output_path: Option<String>,
+    raw: bool,
+) -> BoxFuture<(), Error> {
+    let contents = match FileContents::from_blob(value.into()) {
+        Ok(contents) => contents,
+        Err(e) => return future::err(e).boxify(),
+    };
+
+    match contents {
+        FileContents::Bytes(bytes) => {
+            let size = bytes.len() as u64;
+            future::result(write_contents(output_path, raw, size, bytes)).boxify()
+        }
+        FileContents::Chunked(chunked) => {
+            let total_size: u64 = chunked.chunks().iter().map(|chunk| chunk.size()).sum();
+            let chunk_ids: Vec<_> = chunked
+                .chunks()
+                .iter()
+                .map(|chunk| chunk.chunk_id())
+                .collect();
+
+            stream::iter_ok(chunk_ids)
+                .and_then(move |chunk_id| {
+                    cloned!(ctx, blobstore);
+                    blobstore
+                        .get(ctx, chunk_id.blobstore_key())
+                        .and_then(move |maybe_blob| {
+                            maybe_blob.ok_or_else(|| format_err!("missing chunk {:?}", chunk_id))
+                        })
+                        .and_then(|blob| {
+                            ContentChunk::from_blob(blob.into()).map(ContentChunk::into_bytes)
+                        })
+                })
+                .fold(BytesMut::new(), |mut acc, chunk_bytes| {
+                    acc.extend_from_slice(&chunk_bytes);
+                    future::ok::<_, Error>(acc)
+                })
+                .and_then(move |acc| write_contents(output_path, raw, total_size, acc.freeze()))
+                .boxify()
+        }
+    }
+}
+
+fn write_contents(
+    output_path: Option<String>,
+    raw: bool,
+    total_size: u64,
+    bytes: bytes::Bytes,
+) -> Result<()> {
+    match output_path {
+        Some(path) => {
+            let mut file = File::create(&path)?;
+            file.write_all(&bytes)?;
+        }
+        None => {
+            io::stdout().write_all(&bytes)?;
+        }
+    }
+    if !raw {
+        eprintln!("{} bytes written", total_size);
+    }
+    Ok(())
+}
+
 fn detect_decode(key: &str, logger: &Logger) -> Option<&'static str> {
     // Use a simple heuristic to figure out how to decode this key.
     if key.find("hgchangeset.").is_some() {
@@ -245,3 +552,29 @@ fn get_cache<B: CacheBlobstoreExt>(
         blobstore.get(ctx, key)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json_escape_passes_plain_text_through_unchanged() {
+        assert_eq!(json_escape("hello world"), "\"hello world\"");
+    }
+
+    #[test]
+    fn json_escape_escapes_quotes_and_backslashes() {
+        assert_eq!(json_escape(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+
+    #[test]
+    fn json_escape_escapes_control_characters() {
+        assert_eq!(json_escape("a\nb\rc\td"), "\"a\\nb\\rc\\td\"");
+        assert_eq!(json_escape("\u{1}"), "\"\\u0001\"");
+    }
+
+    #[test]
+    fn json_escape_handles_empty_string() {
+        assert_eq!(json_escape(""), "\"\"");
+    }
+}
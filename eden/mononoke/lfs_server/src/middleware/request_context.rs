@@ -18,12 +18,22 @@ use gotham_ext::middleware::ClientIdentity;
 use gotham_ext::middleware::Middleware;
 use gotham_ext::state_ext::StateExt;
 use hyper::body::Body;
+use hyper::Method;
 use hyper::Response;
+use hyper::StatusCode;
+use hyper::Uri;
 use metadata::Metadata;
 use scuba_ext::MononokeScubaSampleBuilder;
 use slog::o;
 use slog::Logger;
 
+mod throttle;
+
+use throttle::IdentityKey;
+use throttle::ThrottleConfig;
+use throttle::ThrottleCounters;
+use throttle::Throttler;
+
 #[derive(Copy, Clone)]
 pub enum LfsMethod {
     Upload,
@@ -87,11 +97,28 @@ impl RequestContext {
 pub struct RequestContextMiddleware {
     fb: FacebookInit,
     logger: Logger,
+    throttler: Throttler,
 }
 
 impl RequestContextMiddleware {
     pub fn new(fb: FacebookInit, logger: Logger) -> Self {
-        Self { fb, logger }
+        Self {
+            fb,
+            logger,
+            throttler: Throttler::new(ThrottleConfig::default()),
+        }
+    }
+
+    /// Override the default per-identity rate/concurrency limits.
+    pub fn with_throttle_config(mut self, config: ThrottleConfig) -> Self {
+        self.throttler = Throttler::new(config);
+        self
+    }
+
+    /// Counts of allowed vs. throttled requests since this middleware was
+    /// created, for exporting alongside the rest of the server's stats.
+    pub fn throttle_counters(&self) -> ThrottleCounters {
+        self.throttler.counters()
     }
 }
 
@@ -101,23 +128,81 @@ impl Middleware for RequestContextMiddleware {
         let request_id = state.short_request_id();
 
         let logger = self.logger.new(o!("request_id" => request_id.to_string()));
-        let (should_log, identities, address) =
+        let (is_proxygen_test, identities, address) =
             if let Some(client_identity) = ClientIdentity::try_borrow_from(state) {
                 (
-                    !client_identity.is_proxygen_test_identity(),
+                    client_identity.is_proxygen_test_identity(),
                     client_identity.identities().clone().unwrap_or_default(),
                     client_identity.address().clone(),
                 )
             } else {
-                (true, Default::default(), None)
+                (false, Default::default(), None)
             };
+        let should_log = !is_proxygen_test;
+
+        let throttle_key = {
+            let mut names: Vec<String> = identities.iter().map(|id| id.to_string()).collect();
+            names.sort();
+            if names.is_empty() {
+                match address {
+                    Some(addr) => IdentityKey::Address(addr),
+                    None => IdentityKey::Unknown,
+                }
+            } else {
+                IdentityKey::Identity(names.join(","))
+            }
+        };
+
         let metadata = Metadata::new(None, identities, false, address).await;
         let session = SessionContainer::builder(self.fb)
             .metadata(Arc::new(metadata))
             .build();
         let ctx = session.new_context(logger, MononokeScubaSampleBuilder::with_discard());
 
-        state.put(RequestContext::new(ctx, should_log));
+        let mut request_context = RequestContext::new(ctx, should_log);
+
+        if !is_proxygen_test {
+            // The precise `LfsMethod` isn't known until routing hands the
+            // request to its handler and calls `set_request` -- and by then
+            // it's too late to short-circuit with a response, since only
+            // `Middleware::inbound` can do that. So `LfsMethod::is_read_only`
+            // is approximated here instead: HTTP verb for most routes, but
+            // with the batch endpoint special-cased, since it's a `POST`
+            // that `LfsMethod::Batch` nonetheless classifies as read-only
+            // (it just describes download locations, it doesn't transfer
+            // object data) -- lumping it in with uploads would throttle
+            // ordinary download traffic against the much smaller write budget.
+            let is_batch = Uri::try_borrow_from(state)
+                .map(|uri| uri.path().ends_with("/batch"))
+                .unwrap_or(false);
+            let is_read_only = is_batch
+                || Method::try_borrow_from(state)
+                    .map(|method| *method == Method::GET || *method == Method::HEAD)
+                    .unwrap_or(true);
+
+            match self.throttler.check(throttle_key, is_read_only) {
+                Ok(guard) => {
+                    state.put(guard);
+                }
+                Err(retry_after) => {
+                    request_context.error_msg = Some(format!(
+                        "throttled: too many {} requests",
+                        if is_read_only { "read" } else { "write" }
+                    ));
+                    state.put(request_context);
+
+                    let response = Response::builder()
+                        .status(StatusCode::TOO_MANY_REQUESTS)
+                        .header("Retry-After", retry_after.as_secs().max(1).to_string())
+                        .body(Body::from("request throttled, please retry later\n"))
+                        .expect("building a throttle response should never fail");
+
+                    return Some(response);
+                }
+            }
+        }
+
+        state.put(request_context);
 
         None
     }
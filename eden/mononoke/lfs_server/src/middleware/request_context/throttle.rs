@@ -0,0 +1,369 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Per-identity (falling back to per-address) request throttling.
+//!
+//! Each caller gets a token bucket for request rate and a concurrency
+//! counter for in-flight requests, with separate budgets for read-only and
+//! write traffic so a runaway uploader can't starve downloads. State is
+//! keyed by `IdentityKey` in one map behind a single mutex and swept for idle
+//! entries periodically, since the set of callers seen over the lifetime
+//! of a long-running server is unbounded.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use gotham_derive::StateData;
+
+/// How many `check()` calls between sweeps of `state` for identities that
+/// haven't been seen in `IDLE_TTL`.
+const PRUNE_EVERY: u64 = 1024;
+const IDLE_TTL: Duration = Duration::from_secs(300);
+
+/// Token-bucket parameters for one traffic class (read-only or write), plus
+/// how many requests of that class a single identity may have in flight at
+/// once.
+#[derive(Copy, Clone, Debug)]
+pub struct ThrottleLimits {
+    /// Tokens regenerated per second.
+    pub refill_per_sec: f64,
+    /// Maximum tokens a bucket can hold, i.e. the burst size.
+    pub burst: f64,
+    /// Maximum concurrent in-flight requests of this class, per identity.
+    pub max_concurrency: usize,
+}
+
+impl Default for ThrottleLimits {
+    fn default() -> Self {
+        Self {
+            refill_per_sec: 50.0,
+            burst: 100.0,
+            max_concurrency: 64,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ThrottleConfig {
+    pub reads: ThrottleLimits,
+    pub writes: ThrottleLimits,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            reads: ThrottleLimits::default(),
+            // Writes get a noticeably smaller budget than reads: the goal
+            // is specifically to stop a runaway uploader from starving
+            // downloads, not to balance the two evenly.
+            writes: ThrottleLimits {
+                refill_per_sec: 10.0,
+                burst: 20.0,
+                max_concurrency: 16,
+            },
+        }
+    }
+}
+
+/// Identifies who a throttle bucket belongs to. An identity is preferred
+/// when the caller presented one, so callers behind a shared proxy or NAT
+/// still get their own budget; otherwise this falls back to source address.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum IdentityKey {
+    Identity(String),
+    Address(IpAddr),
+    Unknown,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    in_flight: usize,
+}
+
+impl Bucket {
+    /// A fresh bucket starts full, not empty -- otherwise a brand-new
+    /// identity's very first request would always find `tokens < 1.0` and
+    /// be throttled before it ever got a chance to refill.
+    fn new(now: Instant, burst: f64) -> Self {
+        Self {
+            tokens: burst,
+            last_refill: now,
+            in_flight: 0,
+        }
+    }
+
+    /// Refill based on elapsed time, then try to take one token and reserve
+    /// one concurrency slot. On failure, returns how long the caller should
+    /// wait before retrying.
+    fn try_acquire(&mut self, limits: &ThrottleLimits, now: Instant) -> Result<(), Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limits.refill_per_sec).min(limits.burst);
+        self.last_refill = now;
+
+        if self.in_flight >= limits.max_concurrency {
+            return Err(Duration::from_millis(250));
+        }
+
+        if self.tokens < 1.0 {
+            let deficit = 1.0 - self.tokens;
+            let wait = if limits.refill_per_sec > 0.0 {
+                Duration::from_secs_f64(deficit / limits.refill_per_sec)
+            } else {
+                Duration::from_secs(1)
+            };
+            return Err(wait.max(Duration::from_secs(1)));
+        }
+
+        self.tokens -= 1.0;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+    }
+}
+
+struct PerIdentityState {
+    reads: Bucket,
+    writes: Bucket,
+    last_access: Instant,
+}
+
+impl PerIdentityState {
+    fn new(now: Instant, config: &ThrottleConfig) -> Self {
+        Self {
+            reads: Bucket::new(now, config.reads.burst),
+            writes: Bucket::new(now, config.writes.burst),
+            last_access: now,
+        }
+    }
+}
+
+/// Counters exposed for logging/monitoring.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct ThrottleCounters {
+    pub allowed: u64,
+    pub throttled: u64,
+}
+
+struct Inner {
+    config: ThrottleConfig,
+    state: Mutex<HashMap<IdentityKey, PerIdentityState>>,
+    checks: AtomicU64,
+    allowed: AtomicU64,
+    throttled: AtomicU64,
+}
+
+/// Shared, thread-safe throttling state for one `RequestContextMiddleware`.
+#[derive(Clone)]
+pub struct Throttler {
+    inner: Arc<Inner>,
+}
+
+impl Throttler {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                state: Mutex::new(HashMap::new()),
+                checks: AtomicU64::new(0),
+                allowed: AtomicU64::new(0),
+                throttled: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn counters(&self) -> ThrottleCounters {
+        ThrottleCounters {
+            allowed: self.inner.allowed.load(Ordering::Relaxed),
+            throttled: self.inner.throttled.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Check whether `key` may proceed with a request of the given
+    /// read-only-ness, reserving a token and a concurrency slot if so. On
+    /// success, returns a guard that releases the concurrency slot when
+    /// dropped (e.g. along with the rest of the request's `State`). On
+    /// failure, returns how long the caller should wait before retrying.
+    pub fn check(&self, key: IdentityKey, is_read_only: bool) -> Result<ThrottleGuard, Duration> {
+        let now = Instant::now();
+        let limits = if is_read_only {
+            &self.inner.config.reads
+        } else {
+            &self.inner.config.writes
+        };
+
+        let result = {
+            let mut state = self
+                .inner
+                .state
+                .lock()
+                .expect("throttle state lock poisoned");
+            let entry = state
+                .entry(key.clone())
+                .or_insert_with(|| PerIdentityState::new(now, &self.inner.config));
+            entry.last_access = now;
+            let bucket = if is_read_only {
+                &mut entry.reads
+            } else {
+                &mut entry.writes
+            };
+            bucket.try_acquire(limits, now)
+        };
+
+        if self.inner.checks.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY == 0 {
+            self.prune(now);
+        }
+
+        match result {
+            Ok(()) => {
+                self.inner.allowed.fetch_add(1, Ordering::Relaxed);
+                Ok(ThrottleGuard {
+                    throttler: self.clone(),
+                    key,
+                    is_read_only,
+                })
+            }
+            Err(retry_after) => {
+                self.inner.throttled.fetch_add(1, Ordering::Relaxed);
+                Err(retry_after)
+            }
+        }
+    }
+
+    fn release(&self, key: &IdentityKey, is_read_only: bool) {
+        let mut state = self
+            .inner
+            .state
+            .lock()
+            .expect("throttle state lock poisoned");
+        if let Some(entry) = state.get_mut(key) {
+            if is_read_only {
+                entry.reads.release();
+            } else {
+                entry.writes.release();
+            }
+        }
+    }
+
+    fn prune(&self, now: Instant) {
+        let mut state = self
+            .inner
+            .state
+            .lock()
+            .expect("throttle state lock poisoned");
+        state.retain(|_, entry| now.saturating_duration_since(entry.last_access) < IDLE_TTL);
+    }
+}
+
+/// Releases this request's reserved concurrency slot when dropped. Intended
+/// to be stashed in the Gotham `State` for the duration of the request so
+/// the slot is freed automatically once the request finishes, however it
+/// finishes.
+#[derive(StateData)]
+pub struct ThrottleGuard {
+    throttler: Throttler,
+    key: IdentityKey,
+    is_read_only: bool,
+}
+
+impl Drop for ThrottleGuard {
+    fn drop(&mut self) {
+        self.throttler.release(&self.key, self.is_read_only);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> ThrottleLimits {
+        ThrottleLimits {
+            refill_per_sec: 10.0,
+            burst: 5.0,
+            max_concurrency: 2,
+        }
+    }
+
+    #[test]
+    fn fresh_bucket_allows_a_full_burst_up_front() {
+        let limits = limits();
+        let now = Instant::now();
+        let mut bucket = Bucket::new(now, limits.burst);
+
+        // A brand-new identity should be able to take `burst` requests
+        // immediately, not be throttled on its very first request while
+        // waiting to "fill up".
+        for _ in 0..limits.burst as usize {
+            bucket.release();
+            assert!(bucket.try_acquire(&limits, now).is_ok());
+        }
+    }
+
+    #[test]
+    fn exhausted_tokens_are_throttled_with_a_positive_wait() {
+        let limits = limits();
+        let now = Instant::now();
+        let mut bucket = Bucket::new(now, limits.burst);
+
+        for _ in 0..limits.burst as usize {
+            bucket.release();
+            bucket.try_acquire(&limits, now).unwrap();
+        }
+
+        bucket.release();
+        let wait = bucket.try_acquire(&limits, now).unwrap_err();
+        assert!(wait > Duration::from_secs(0));
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let limits = limits();
+        let now = Instant::now();
+        let mut bucket = Bucket::new(now, limits.burst);
+
+        for _ in 0..limits.burst as usize {
+            bucket.release();
+            bucket.try_acquire(&limits, now).unwrap();
+        }
+        bucket.release();
+        assert!(bucket.try_acquire(&limits, now).is_err());
+
+        // One second later, `refill_per_sec` tokens should have come back
+        // (capped at `burst`).
+        let later = now + Duration::from_secs(1);
+        bucket.release();
+        assert!(bucket.try_acquire(&limits, later).is_ok());
+    }
+
+    #[test]
+    fn concurrency_limit_is_enforced_independently_of_tokens() {
+        let limits = limits();
+        let now = Instant::now();
+        let mut bucket = Bucket::new(now, limits.burst);
+
+        // Plenty of tokens available, but only `max_concurrency` requests
+        // may be in flight at once.
+        for _ in 0..limits.max_concurrency {
+            bucket.try_acquire(&limits, now).unwrap();
+        }
+        assert!(bucket.try_acquire(&limits, now).is_err());
+
+        bucket.release();
+        assert!(bucket.try_acquire(&limits, now).is_ok());
+    }
+}
@@ -2,27 +2,33 @@
 
 use std::{
     cmp,
+    collections::BinaryHeap,
+    mem,
     sync::mpsc::channel,
     sync::{Arc, Mutex, MutexGuard},
     thread,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
 use curl::{
     self,
-    easy::{Easy2, Handler, HttpVersion, List},
+    easy::{Easy2, Handler, HttpVersion, List, WriteError},
     multi::Multi,
 };
-use failure::{bail, ensure, err_msg, Fallible};
-use itertools::Itertools;
+use failure::{bail, ensure, err_msg, format_err, Fallible};
 use log;
 use serde::{de::DeserializeOwned, Serialize};
 use serde_cbor::Deserializer;
 use url::Url;
 
 use driver::MultiDriver;
+use endpoint::EndpointPool;
 use handler::Collector;
+use retry::{
+    is_retryable_curl_error, is_retryable_status, sleep_until_next_retry, PendingRetry,
+    RetryCollector, RetryPolicy, RetryQueue,
+};
 use revisionstore::{Delta, Metadata, MutableDeltaStore, MutableHistoryStore};
 use types::{
     api::{DataRequest, DataResponse, HistoryRequest, HistoryResponse, TreeRequest},
@@ -34,8 +40,13 @@ use crate::config::{ClientCreds, Config};
 use crate::progress::{ProgressFn, ProgressManager};
 use crate::stats::DownloadStats;
 
+mod async_client;
 mod driver;
+mod endpoint;
 mod handler;
+mod retry;
+
+pub use async_client::{DataEntryStream, EdenApiAsync, HistoryEntryStream};
 
 mod paths {
     pub const HEALTH_CHECK: &str = "/health_check";
@@ -79,6 +90,33 @@ impl SyncMulti {
 unsafe impl Send for SyncMulti {}
 unsafe impl Sync for SyncMulti {}
 
+/// Observes data flowing through an `EdenApiCurlClient`, for corpus
+/// generation, cache-warming traces, or auditing exactly which keys and
+/// byte volumes a client pulled over the network. Mirrors the
+/// `SamplingHandler` pattern used by `SamplingBlobstore`, but for this
+/// crate's HTTP layer rather than a local blobstore.
+///
+/// Every method has a no-op default, so an implementor only needs to
+/// override the signals it cares about.
+pub trait FetchSamplingHandler: Send + Sync {
+    /// Called once per fetched file or tree blob, with its decoded bytes,
+    /// right before it is handed to the `MutableDeltaStore`.
+    fn sample_data(&self, _key: &Key, _bytes: &Bytes) -> Fallible<()> {
+        Ok(())
+    }
+
+    /// Called once per fetched history entry, right before it is handed
+    /// to the `MutableHistoryStore`.
+    fn sample_history(&self, _entry: &HistoryEntry) -> Fallible<()> {
+        Ok(())
+    }
+
+    /// Called once per outgoing batched request, just before it is sent.
+    fn sample_request(&self, _url: &Url, _num_keys: usize, _bytes_uploaded: usize) -> Fallible<()> {
+        Ok(())
+    }
+}
+
 pub struct EdenApiCurlClient {
     multi: SyncMulti,
     base_url: Url,
@@ -90,6 +128,34 @@ pub struct EdenApiCurlClient {
     stream_data: bool,
     stream_history: bool,
     stream_trees: bool,
+    retry_policy: RetryPolicy,
+    timeouts: TimeoutConfig,
+    endpoints: Arc<EndpointPool>,
+    /// Byte budget for a single batched request's CBOR payload. Seeded from
+    /// `Config::max_request_bytes`, but replaced by a server's
+    /// self-advertised limit (larger or smaller) observed on a `health_check`.
+    max_request_bytes: Mutex<Option<usize>>,
+    /// Optional observer notified of every fetched key/blob, history entry,
+    /// and outgoing batched request. No-op unless set via
+    /// `with_sampling_handler`. Only the synchronous `EdenApi` methods
+    /// notify it; fetches made through `EdenApiAsync` do not.
+    sampling_handler: Option<Arc<dyn FetchSamplingHandler>>,
+}
+
+/// Per-transfer timeout knobs applied to every `Easy2` handle this client
+/// creates, so that a single stalled connection can't wedge the `Multi`
+/// loop (and, by extension, every other concurrent transfer sharing it)
+/// forever.
+#[derive(Clone, Copy, Default)]
+struct TimeoutConfig {
+    /// Overall cap on establishing the connection.
+    connect_timeout: Option<Duration>,
+    /// Overall cap on the whole transfer, from start to finish.
+    timeout: Option<Duration>,
+    /// Paired with `low_speed_time`: abort if the transfer sustains a rate
+    /// below this many bytes/sec for that long.
+    low_speed_limit: Option<u32>,
+    low_speed_time: Option<Duration>,
 }
 
 // Public API.
@@ -105,6 +171,29 @@ impl EdenApiCurlClient {
             None => bail!("No repo name specified"),
         };
 
+        let retry_policy = RetryPolicy {
+            max_retries: config.max_retries.unwrap_or(RetryPolicy::default().max_retries),
+            base_backoff: config
+                .retry_base_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or_else(|| RetryPolicy::default().base_backoff),
+            max_backoff: config
+                .retry_max_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or_else(|| RetryPolicy::default().max_backoff),
+        };
+
+        let timeouts = TimeoutConfig {
+            connect_timeout: config.connect_timeout_ms.map(Duration::from_millis),
+            timeout: config.timeout_ms.map(Duration::from_millis),
+            low_speed_limit: config.low_speed_limit_bytes,
+            low_speed_time: config.low_speed_time_secs.map(Duration::from_secs),
+        };
+
+        let mut mirror_urls = config.mirror_urls.unwrap_or_default();
+        mirror_urls.insert(0, base_url.clone());
+        let endpoints = Arc::new(EndpointPool::new(mirror_urls));
+
         Ok(Self {
             multi: SyncMulti::new(),
             base_url,
@@ -116,14 +205,39 @@ impl EdenApiCurlClient {
             stream_data: config.stream_data,
             stream_history: config.stream_history,
             stream_trees: config.stream_trees,
+            retry_policy,
+            timeouts,
+            endpoints,
+            max_request_bytes: Mutex::new(config.max_request_bytes),
+            sampling_handler: None,
         })
     }
+
+    /// Attach a handler that observes every fetched key/blob, history
+    /// entry, and outgoing batched request made through the `EdenApi`
+    /// methods -- e.g. for corpus generation, cache-warming traces, or
+    /// auditing. `EdenApiAsync` fetches are not reported to it.
+    pub fn with_sampling_handler(mut self, handler: Arc<dyn FetchSamplingHandler>) -> Self {
+        self.sampling_handler = Some(handler);
+        self
+    }
+
+    /// The current byte budget for a single batched request: the server's
+    /// self-advertised limit from the last successful `health_check`, if
+    /// any, otherwise whatever `Config::max_request_bytes` was configured
+    /// with.
+    fn max_request_bytes(&self) -> Option<usize> {
+        *self
+            .max_request_bytes
+            .lock()
+            .expect("max request bytes lock poisoned")
+    }
 }
 
 impl EdenApi for EdenApiCurlClient {
     fn health_check(&self) -> Fallible<()> {
-        let handler = Collector::new();
-        let mut handle = new_easy_handle(self.creds.as_ref(), handler)?;
+        let handler = CapabilityCollector::new();
+        let mut handle = new_easy_handle(self.creds.as_ref(), &self.timeouts, handler)?;
         let url = self.base_url.join(paths::HEALTH_CHECK)?;
         handle.url(url.as_str())?;
         handle.get(true)?;
@@ -132,19 +246,33 @@ impl EdenApi for EdenApiCurlClient {
         let code = handle.response_code()?;
         ensure!(code == 200, "Received HTTP status code {}", code);
 
-        let response = String::from_utf8_lossy(&handle.get_ref().data());
+        let response = String::from_utf8_lossy(handle.get_ref().data());
         ensure!(
             response == "I_AM_ALIVE",
             "Unexpected response: {:?}",
             &response
         );
 
+        // Let the server self-tune our batch sizing: if it advertises a
+        // maximum request payload, prefer that over whatever was in
+        // `Config` until the next health check says otherwise.
+        if let Some(max_bytes) = handle
+            .get_ref()
+            .header("x-eden-max-request-bytes")
+            .and_then(|value| value.parse::<usize>().ok())
+        {
+            *self
+                .max_request_bytes
+                .lock()
+                .expect("max request bytes lock poisoned") = Some(max_bytes);
+        }
+
         Ok(())
     }
 
     fn hostname(&self) -> Fallible<String> {
         let handler = Collector::new();
-        let mut handle = new_easy_handle(self.creds.as_ref(), handler)?;
+        let mut handle = new_easy_handle(self.creds.as_ref(), &self.timeouts, handler)?;
         let url = self.base_url.join(paths::HOSTNAME)?;
         handle.url(url.as_str())?;
         handle.get(true)?;
@@ -175,22 +303,34 @@ impl EdenApi for EdenApiCurlClient {
     ) -> Fallible<DownloadStats> {
         log::debug!("Fetching {} files", keys.len());
 
-        let mut url = self.repo_base_url()?.join(paths::HISTORY)?;
-        if self.stream_history {
-            url.set_query(Some("stream=true"));
-        }
+        let query = if self.stream_history {
+            Some("stream=true")
+        } else {
+            None
+        };
 
         let batch_size = self.history_batch_size.unwrap_or(cmp::max(keys.len(), 1));
-        let num_requests = (keys.len() + batch_size - 1) / batch_size;
+        let batches = batch_keys(keys, batch_size, self.max_request_bytes());
+        let num_requests = batches.len();
 
         log::debug!("Using batch size: {}", batch_size);
         log::debug!("Preparing {} requests", num_requests);
 
-        let chunks = keys.into_iter().chunks(batch_size);
-        let requests = (&chunks).into_iter().map(|batch| HistoryRequest {
-            keys: batch.into_iter().collect(),
-            depth: max_depth,
-        });
+        let requests = batches
+            .into_iter()
+            .map(|batch| HistoryRequest {
+                keys: batch,
+                depth: max_depth,
+            })
+            .collect::<Vec<_>>();
+
+        let dispatch_cb = |request: &HistoryRequest, endpoint: &Url| -> Fallible<()> {
+            if let Some(sampler) = &self.sampling_handler {
+                let bytes_uploaded = serde_cbor::to_vec(request).map(|b| b.len()).unwrap_or(0);
+                sampler.sample_request(endpoint, request.keys.len(), bytes_uploaded)?;
+            }
+            Ok(())
+        };
 
         let mut multi = self.multi.lock();
 
@@ -199,15 +339,24 @@ impl EdenApi for EdenApiCurlClient {
         let stats = if self.stream_history {
             multi_request(
                 &mut multi,
-                &url,
+                &self.endpoints,
+                &self.repo,
+                paths::HISTORY,
+                query,
                 self.creds.as_ref(),
+                &self.timeouts,
                 requests,
+                &self.retry_policy,
                 progress,
+                dispatch_cb,
                 |response: Vec<(RepoPathBuf, WireHistoryEntry)>| {
                     num_responses += 1;
                     for (path, entry) in response {
                         num_entries += 1;
                         let entry = HistoryEntry::from_wire(entry, path);
+                        if let Some(sampler) = &self.sampling_handler {
+                            sampler.sample_history(&entry)?;
+                        }
                         store.add_entry(&entry)?;
                     }
                     Ok(())
@@ -216,14 +365,23 @@ impl EdenApi for EdenApiCurlClient {
         } else {
             multi_request(
                 &mut multi,
-                &url,
+                &self.endpoints,
+                &self.repo,
+                paths::HISTORY,
+                query,
                 self.creds.as_ref(),
+                &self.timeouts,
                 requests,
+                &self.retry_policy,
                 progress,
+                dispatch_cb,
                 |response: Vec<HistoryResponse>| {
                     num_responses += 1;
                     for entry in response.into_iter().flatten() {
                         num_entries += 1;
+                        if let Some(sampler) = &self.sampling_handler {
+                            sampler.sample_history(&entry)?;
+                        }
                         store.add_entry(&entry)?;
                     }
                     Ok(())
@@ -257,36 +415,59 @@ impl EdenApi for EdenApiCurlClient {
         store: &mut MutableDeltaStore,
         progress: Option<ProgressFn>,
     ) -> Fallible<DownloadStats> {
-        let mut url = self.repo_base_url()?.join(paths::PREFETCH_TREES)?;
-        if self.stream_trees {
-            url.set_query(Some("stream=true"));
-        }
+        let query = if self.stream_trees {
+            Some("stream=true")
+        } else {
+            None
+        };
 
         let creds = self.creds.as_ref();
+        let num_keys = mfnodes.len();
         let requests = vec![TreeRequest::new(rootdir, mfnodes, basemfnodes, depth)];
 
+        let sampling_handler = self.sampling_handler.clone();
+        let dispatch_cb = move |request: &TreeRequest, endpoint: &Url| -> Fallible<()> {
+            if let Some(sampler) = &sampling_handler {
+                let bytes_uploaded = serde_cbor::to_vec(request).map(|b| b.len()).unwrap_or(0);
+                sampler.sample_request(endpoint, num_keys, bytes_uploaded)?;
+            }
+            Ok(())
+        };
+
         if self.stream_trees {
             multi_request_threaded(
                 self.multi.clone(),
-                &url,
+                self.endpoints.clone(),
+                self.repo.clone(),
+                paths::PREFETCH_TREES,
+                query,
                 creds,
+                self.timeouts,
                 requests,
+                self.retry_policy,
                 progress,
+                dispatch_cb,
                 |entries| {
                     let response = DataResponse::new(entries);
-                    add_data_response(store, response, self.validate)
+                    add_data_response(store, response, self.validate, self.sampling_handler.as_ref())
                 },
             )
         } else {
             multi_request_threaded(
                 self.multi.clone(),
-                &url,
+                self.endpoints.clone(),
+                self.repo.clone(),
+                paths::PREFETCH_TREES,
+                query,
                 creds,
+                self.timeouts,
                 requests,
+                self.retry_policy,
                 progress,
+                dispatch_cb,
                 |responses| {
                     for response in responses {
-                        add_data_response(store, response, self.validate)?;
+                        add_data_response(store, response, self.validate, self.sampling_handler.as_ref())?;
                     }
                     Ok(())
                 },
@@ -297,64 +478,82 @@ impl EdenApi for EdenApiCurlClient {
 
 // Private methods.
 impl EdenApiCurlClient {
-    fn repo_base_url(&self) -> Fallible<Url> {
-        Ok(self.base_url.join(&format!("{}/", &self.repo))?)
-    }
-
     fn get_data(
         &self,
-        path: &str,
+        path: &'static str,
         keys: Vec<Key>,
         store: &mut MutableDeltaStore,
         progress: Option<ProgressFn>,
     ) -> Fallible<DownloadStats> {
         log::debug!("Fetching data for {} keys", keys.len());
 
-        let mut url = self.repo_base_url()?.join(path)?;
-        if self.stream_data {
-            url.set_query(Some("stream=true"));
-        }
+        let query = if self.stream_data {
+            Some("stream=true")
+        } else {
+            None
+        };
 
         let batch_size = self.data_batch_size.unwrap_or(cmp::max(keys.len(), 1));
-        let num_requests = (keys.len() + batch_size - 1) / batch_size;
+        let batches = batch_keys(keys, batch_size, self.max_request_bytes());
+        let num_requests = batches.len();
 
         log::debug!("Using batch size: {}", batch_size);
         log::debug!("Preparing {} requests", num_requests);
 
-        let mut requests = Vec::with_capacity(num_requests);
-        for batch in &keys.into_iter().chunks(batch_size) {
-            let keys = batch.into_iter().collect();
-            requests.push(DataRequest { keys });
-        }
+        let requests = batches
+            .into_iter()
+            .map(|keys| DataRequest { keys })
+            .collect::<Vec<_>>();
+
+        let sampling_handler = self.sampling_handler.clone();
+        let dispatch_cb = move |request: &DataRequest, endpoint: &Url| -> Fallible<()> {
+            if let Some(sampler) = &sampling_handler {
+                let bytes_uploaded = serde_cbor::to_vec(request).map(|b| b.len()).unwrap_or(0);
+                sampler.sample_request(endpoint, request.keys.len(), bytes_uploaded)?;
+            }
+            Ok(())
+        };
 
         let mut num_responses = 0;
         let mut num_entries = 0;
         let stats = if self.stream_data {
             multi_request_threaded(
                 self.multi.clone(),
-                &url,
+                self.endpoints.clone(),
+                self.repo.clone(),
+                path,
+                query,
                 self.creds.as_ref(),
+                self.timeouts,
                 requests,
+                self.retry_policy,
                 progress,
+                dispatch_cb,
                 |entries: Vec<DataEntry>| {
                     num_responses += 1;
                     num_entries += entries.len();
                     let response = DataResponse::new(entries);
-                    add_data_response(store, response, self.validate)
+                    add_data_response(store, response, self.validate, self.sampling_handler.as_ref())
                 },
             )?
         } else {
             multi_request_threaded(
                 self.multi.clone(),
-                &url,
+                self.endpoints.clone(),
+                self.repo.clone(),
+                path,
+                query,
                 self.creds.as_ref(),
+                self.timeouts,
                 requests,
+                self.retry_policy,
                 progress,
+                dispatch_cb,
                 |responses: Vec<DataResponse>| {
                     for response in responses {
                         num_responses += 1;
                         num_entries += response.entries.len();
-                        add_data_response(store, response, self.validate)?;
+                        add_data_response(store, response, self.validate, self.sampling_handler.as_ref())?;
                     }
                     Ok(())
                 },
@@ -374,60 +573,171 @@ impl EdenApiCurlClient {
 /// CBOR payload of each respective request. Assumes that the responses are
 /// CBOR encoded, and automatically deserializes them before passing
 /// them to the given callback.
-fn multi_request<'a, R, I, T, F>(
+///
+/// Each request is sent to whichever endpoint in `endpoints` currently looks
+/// fastest and healthiest; the outcome of every attempt is fed back into the
+/// pool via `record_success`/`record_failure` so later requests (and later
+/// retries within this same call) keep making that choice with fresh data.
+/// `dispatch_cb` is invoked once per request, right as it's about to be
+/// sent, with the endpoint it was actually assigned -- so a caller that
+/// wants to attribute upload bytes to a server (e.g. for sampling) gets the
+/// real destination rather than having to guess at it before dispatch.
+///
+/// A handle whose transfer fails with a retryable condition (a transport
+/// error like a timeout, or an HTTP 429/500/502/503/504) is not surfaced as
+/// an error; instead it is re-armed on `multi`, possibly against a different
+/// endpoint, after an exponential backoff (honoring a `Retry-After` header
+/// when present), up to `retry_policy.max_retries` attempts. This runs in
+/// rounds: handles that need to be retried are collected into a min-heap
+/// keyed by the instant they become eligible again, we sleep until the
+/// earliest of those, then re-submit everything that's ready and perform
+/// another round.
+fn multi_request<'a, R, I, T, D, F>(
     multi: &'a mut Multi,
-    url: &Url,
+    endpoints: &EndpointPool,
+    repo: &str,
+    path: &str,
+    query: Option<&str>,
     creds: Option<&ClientCreds>,
+    timeouts: &TimeoutConfig,
     requests: I,
+    retry_policy: &RetryPolicy,
     progress_cb: Option<ProgressFn>,
+    mut dispatch_cb: D,
     mut response_cb: F,
 ) -> Fallible<DownloadStats>
 where
     R: Serialize,
     I: IntoIterator<Item = R>,
     T: DeserializeOwned,
+    D: FnMut(&R, &Url) -> Fallible<()>,
     F: FnMut(Vec<T>) -> Fallible<()>,
 {
     let requests = requests.into_iter().collect::<Vec<_>>();
     let num_requests = requests.len();
 
     let mut progress = ProgressManager::with_capacity(num_requests);
-    let mut driver = MultiDriver::with_capacity(multi, num_requests);
-    driver.fail_early(true);
 
-    for request in requests {
+    let mut initial = Vec::with_capacity(num_requests);
+    for request in &requests {
         let handle = progress.register();
-        let handler = Collector::with_progress(handle);
-        let mut easy = new_easy_handle(creds, handler)?;
-        prepare_cbor_post(&mut easy, &url, &request)?;
-        driver.add(easy)?;
+        let endpoint = endpoints.best();
+        let url = build_request_url(&endpoint, repo, path, query)?;
+        dispatch_cb(request, &endpoint)?;
+        let handler = RetryCollector::new(endpoint, Some(handle));
+        let mut easy = new_easy_handle(creds, timeouts, handler)?;
+        prepare_cbor_post(&mut easy, &url, request)?;
+        initial.push(easy);
     }
 
+    let mut driver = MultiDriver::with_capacity(multi, num_requests);
+    // Let every handle in a round finish, instead of aborting as soon as one
+    // fails, so that a single flaky transfer doesn't stop its peers from
+    // completing (or from being considered for retry).
+    driver.fail_early(false);
+
     progress.set_callback(progress_cb);
     driver.set_progress_manager(progress);
 
     log::debug!("Performing {} requests", num_requests);
     let start = Instant::now();
 
-    driver.perform(|res| {
-        let mut easy = res?;
-        let code = easy.response_code()?;
-        let data = easy.get_ref().data();
-
-        if code >= 400 {
-            let msg = String::from_utf8_lossy(data);
-            bail!(
-                "Received HTTP status code {} with response: {:?}",
-                code,
-                msg
-            );
+    let mut retry_queue: RetryQueue<RetryCollector> = BinaryHeap::new();
+    let mut round = initial;
+    let mut num_retried = 0;
+
+    loop {
+        for easy in round {
+            driver.add(easy)?;
         }
 
-        let response = Deserializer::from_slice(data)
-            .into_iter()
-            .collect::<Result<Vec<T>, serde_cbor::error::Error>>()?;
-        response_cb(response)
-    })?;
+        driver.perform(|res| {
+            let mut easy = res?;
+            let attempt = easy.get_ref().attempt();
+
+            let outcome = match easy.response_code() {
+                Ok(code) if code < 400 => Ok(code),
+                Ok(code) => {
+                    if attempt < retry_policy.max_retries && is_retryable_status(code) {
+                        Err(None)
+                    } else {
+                        Err(Some(format_err!(
+                            "Received HTTP status code {} with response: {:?}",
+                            code,
+                            String::from_utf8_lossy(easy.get_ref().data())
+                        )))
+                    }
+                }
+                Err(e) => {
+                    if attempt < retry_policy.max_retries && is_retryable_curl_error(&e) {
+                        Err(None)
+                    } else {
+                        Err(Some(e.into()))
+                    }
+                }
+            };
+
+            match outcome {
+                Ok(_) => {
+                    // `elapsed()` (time since the `RetryCollector` was constructed
+                    // or last reset for a retry) includes however long the handle sat queued
+                    // on `Multi` before curl started the transfer; `curl`'s own
+                    // `starttransfer_time` is time-to-first-byte for the wire
+                    // transfer itself, which is what the EWMA is meant to track.
+                    let latency = easy
+                        .starttransfer_time()
+                        .unwrap_or_else(|_| easy.get_ref().elapsed());
+                    endpoints.record_success(easy.get_ref().endpoint(), latency);
+                    let data = easy.get_ref().data();
+                    let response = Deserializer::from_slice(data)
+                        .into_iter()
+                        .collect::<Result<Vec<T>, serde_cbor::error::Error>>()?;
+                    response_cb(response)
+                }
+                Err(None) => {
+                    endpoints.record_failure(easy.get_ref().endpoint());
+                    let delay = easy
+                        .get_ref()
+                        .retry_after()
+                        .unwrap_or_else(|| retry_policy.backoff(attempt + 1));
+                    let next_endpoint = endpoints.best_excluding(Some(easy.get_ref().endpoint()));
+                    let next_url = build_request_url(&next_endpoint, repo, path, query)?;
+                    easy.url(next_url.as_str())?;
+                    easy.get_mut().reset_for_retry(next_endpoint);
+                    retry_queue.push(PendingRetry::new(easy, delay));
+                    Ok(())
+                }
+                Err(Some(e)) => {
+                    endpoints.record_failure(easy.get_ref().endpoint());
+                    Err(e)
+                }
+            }
+        })?;
+
+        if retry_queue.is_empty() {
+            break;
+        }
+
+        sleep_until_next_retry(&retry_queue);
+
+        let now = Instant::now();
+        let mut next_round = Vec::new();
+        let mut remaining = BinaryHeap::new();
+        for pending in retry_queue.drain() {
+            if pending.ready_at <= now {
+                num_retried += 1;
+                next_round.push(pending.handle);
+            } else {
+                remaining.push(pending);
+            }
+        }
+        retry_queue = remaining;
+        round = next_round;
+    }
+
+    if num_retried > 0 {
+        log::debug!("Retried {} requests", num_retried);
+    }
 
     let elapsed = start.elapsed();
     let progress = driver.progress().unwrap();
@@ -455,24 +765,30 @@ where
 /// run on the main thread. This allows the callback to perform potentially
 /// expensive and/or blocking operations upon receiving a response
 /// without affecting the other ongoing HTTP transfers.
-fn multi_request_threaded<R, I, T, F>(
+fn multi_request_threaded<R, I, T, D, F>(
     multi: SyncMulti,
-    url: &Url,
+    endpoints: Arc<EndpointPool>,
+    repo: String,
+    path: &'static str,
+    query: Option<&'static str>,
     creds: Option<&ClientCreds>,
+    timeouts: TimeoutConfig,
     requests: I,
+    retry_policy: RetryPolicy,
     progress_cb: Option<ProgressFn>,
+    mut dispatch_cb: D,
     mut response_cb: F,
 ) -> Fallible<DownloadStats>
 where
     R: Serialize + Send + 'static,
     I: IntoIterator<Item = R>,
     T: DeserializeOwned + Send + Sync + 'static,
+    D: FnMut(&R, &Url) -> Fallible<()> + Send + 'static,
     F: FnMut(Vec<T>) -> Fallible<()>,
 {
     // Convert arguments to owned types since these will be sent
     // to a new thread, which requires captured values to have a
     // 'static lifetime.
-    let url = url.clone();
     let creds = creds.cloned();
     let requests = requests.into_iter().collect::<Vec<_>>();
 
@@ -482,10 +798,16 @@ where
         let mut multi = multi.lock();
         multi_request(
             &mut multi,
-            &url,
+            &endpoints,
+            &repo,
+            path,
+            query,
             creds.as_ref(),
+            &timeouts,
             requests,
+            &retry_policy,
             progress_cb,
+            move |request: &R, endpoint: &Url| dispatch_cb(request, endpoint),
             |response: Vec<T>| Ok(tx.send(response)?),
         )
     });
@@ -499,8 +821,20 @@ where
         .map_err(|_| err_msg("I/O thread panicked"))?
 }
 
+/// Build the URL for a request against a particular endpoint, mirroring the
+/// `{endpoint}/{repo}/{path}?{query}` layout every EdenAPI server exposes.
+fn build_request_url(endpoint: &Url, repo: &str, path: &str, query: Option<&str>) -> Fallible<Url> {
+    let mut url = endpoint.join(&format!("{}/", repo))?.join(path)?;
+    url.set_query(query);
+    Ok(url)
+}
+
 /// Configure a new curl::Easy2 handle with appropriate default settings.
-fn new_easy_handle<H: Handler>(creds: Option<&ClientCreds>, handler: H) -> Fallible<Easy2<H>> {
+fn new_easy_handle<H: Handler>(
+    creds: Option<&ClientCreds>,
+    timeouts: &TimeoutConfig,
+    handler: H,
+) -> Fallible<Easy2<H>> {
     let mut handle = Easy2::new(handler);
     if let Some(ClientCreds { ref certs, ref key }) = creds {
         handle.ssl_cert(certs)?;
@@ -508,6 +842,20 @@ fn new_easy_handle<H: Handler>(creds: Option<&ClientCreds>, handler: H) -> Falli
     }
     handle.http_version(HttpVersion::V2)?;
     handle.progress(true)?;
+
+    if let Some(connect_timeout) = timeouts.connect_timeout {
+        handle.connect_timeout(connect_timeout)?;
+    }
+    if let Some(timeout) = timeouts.timeout {
+        handle.timeout(timeout)?;
+    }
+    if let Some(low_speed_limit) = timeouts.low_speed_limit {
+        handle.low_speed_limit(low_speed_limit)?;
+    }
+    if let Some(low_speed_time) = timeouts.low_speed_time {
+        handle.low_speed_time(low_speed_time)?;
+    }
+
     Ok(handle)
 }
 
@@ -527,7 +875,16 @@ fn prepare_cbor_post<H, R: Serialize>(easy: &mut Easy2<H>, url: &Url, request: &
     Ok(())
 }
 
-fn add_delta(store: &mut MutableDeltaStore, key: Key, data: Bytes) -> Fallible<()> {
+fn add_delta(
+    store: &mut MutableDeltaStore,
+    key: Key,
+    data: Bytes,
+    sampler: Option<&Arc<dyn FetchSamplingHandler>>,
+) -> Fallible<()> {
+    if let Some(sampler) = sampler {
+        sampler.sample_data(&key, &data)?;
+    }
+
     let metadata = Metadata {
         size: Some(data.len() as u64),
         flags: None,
@@ -545,10 +902,154 @@ fn add_data_response(
     store: &mut MutableDeltaStore,
     response: DataResponse,
     validate: bool,
+    sampler: Option<&Arc<dyn FetchSamplingHandler>>,
 ) -> Fallible<()> {
     for entry in response {
         let data = entry.data(validate)?;
-        add_delta(store, entry.key().clone(), data)?;
+        add_delta(store, entry.key().clone(), data, sampler)?;
     }
     Ok(())
 }
+
+/// Split `keys` into request batches: each batch has at most `max_count`
+/// keys, and -- when `max_bytes` is set -- also stays under that estimated
+/// CBOR-encoded byte budget, so a single request doesn't trip a server's
+/// POST size limit. A key that alone exceeds `max_bytes` is still placed in
+/// its own batch rather than being dropped.
+fn batch_keys(keys: Vec<Key>, max_count: usize, max_bytes: Option<usize>) -> Vec<Vec<Key>> {
+    let max_count = cmp::max(max_count, 1);
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0usize;
+
+    for key in keys {
+        let key_bytes = estimate_key_bytes(&key);
+        let overflows_bytes = match max_bytes {
+            Some(limit) => !batch.is_empty() && batch_bytes + key_bytes > limit,
+            None => false,
+        };
+
+        if batch.len() >= max_count || overflows_bytes {
+            batches.push(mem::take(&mut batch));
+            batch_bytes = 0;
+        }
+
+        batch_bytes += key_bytes;
+        batch.push(key);
+    }
+
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+
+    batches
+}
+
+/// Rough CBOR-encoded size of a single key, used by `batch_keys` to keep
+/// batched request payloads under a server's POST size limit.
+fn estimate_key_bytes(key: &Key) -> usize {
+    serde_cbor::to_vec(key).map(|bytes| bytes.len()).unwrap_or(0)
+}
+
+/// Like `handler::Collector`, but also captures response headers, so
+/// `health_check` can read server-advertised capability hints (e.g. a max
+/// request size) off of them.
+struct CapabilityCollector {
+    data: Vec<u8>,
+    headers: Vec<String>,
+}
+
+impl CapabilityCollector {
+    fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Look up a header by name (case-insensitive), as sent by the server.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.trim().eq_ignore_ascii_case(name) {
+                Some(value.trim())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl Handler for CapabilityCollector {
+    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        self.data.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn header(&mut self, data: &[u8]) -> bool {
+        if let Ok(line) = std::str::from_utf8(data) {
+            self.headers.push(line.trim_end().to_string());
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(path: &str) -> Key {
+        Key::new(RepoPathBuf::from_string(path.to_string()).unwrap(), Node::null_id())
+    }
+
+    #[test]
+    fn batches_split_on_max_count() {
+        let keys = vec![key("a"), key("b"), key("c"), key("d"), key("e")];
+        let batches = batch_keys(keys, 2, None);
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn batches_split_on_byte_budget() {
+        let keys = vec![key("a"), key("b"), key("c"), key("d")];
+        let single_key_bytes = estimate_key_bytes(&key("a"));
+
+        // A budget that only fits two keys per batch should split the four
+        // keys into batches of two, even though `max_count` alone would
+        // have allowed all four in one batch.
+        let batches = batch_keys(keys, 10, Some(single_key_bytes * 2));
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![2, 2]);
+    }
+
+    #[test]
+    fn oversized_key_gets_its_own_batch_instead_of_being_dropped() {
+        let keys = vec![key("a"), key("b")];
+
+        // A budget smaller than even a single key's estimated size must
+        // still place that key in a batch of its own rather than silently
+        // discarding it.
+        let batches = batch_keys(keys, 10, Some(1));
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![1, 1]);
+    }
+
+    #[test]
+    fn no_byte_budget_only_splits_on_max_count() {
+        let keys = vec![key("a"), key("b"), key("c")];
+        let batches = batch_keys(keys, 10, None);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].len(), 3);
+    }
+
+    #[test]
+    fn empty_input_produces_no_batches() {
+        let batches = batch_keys(Vec::new(), 10, Some(1024));
+        assert!(batches.is_empty());
+    }
+}
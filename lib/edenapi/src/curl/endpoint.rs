@@ -0,0 +1,245 @@
+// Copyright Facebook, Inc. 2019
+
+//! A pool of equivalent EdenAPI server endpoints, load-balanced by latency
+//! and avoided while unhealthy.
+//!
+//! Each endpoint tracks an exponentially-weighted moving average of its
+//! observed response latency and a consecutive-failure counter. `best()`
+//! and `best_excluding()` narrow the healthy endpoints down to those within
+//! `LATENCY_TOLERANCE` of the fastest observed latency, then round-robin
+//! within that set -- so load still avoids anything that looks slow or
+//! degraded, but doesn't pile every request from a batch onto a single
+//! "fastest" server, without the caller needing to know which server
+//! actually served a given key.
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+/// Weight given to the newest latency sample when updating the EWMA; a
+/// higher value makes the average react faster to recent transfers.
+const EWMA_ALPHA: f64 = 0.2;
+
+/// Consecutive failures before an endpoint is put into a cooldown period.
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// How long a misbehaving endpoint is skipped before being tried again.
+const COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Endpoints within this factor of the best observed latency are treated as
+/// equally good, and load is round-robined across all of them, rather than
+/// every request pinning to the single lowest-latency endpoint.
+const LATENCY_TOLERANCE: f64 = 1.2;
+
+struct EndpointState {
+    url: Url,
+    ewma_latency: Option<f64>,
+    consecutive_failures: u32,
+    cooldown_until: Option<Instant>,
+}
+
+impl EndpointState {
+    fn is_healthy(&self, now: Instant) -> bool {
+        match self.cooldown_until {
+            Some(until) => now >= until,
+            None => true,
+        }
+    }
+}
+
+/// A pool of server endpoints that are otherwise equivalent (i.e., any of
+/// them can serve any request), load-balanced by observed latency.
+pub struct EndpointPool {
+    endpoints: Mutex<Vec<EndpointState>>,
+    next: AtomicUsize,
+}
+
+impl EndpointPool {
+    pub fn new(urls: Vec<Url>) -> Self {
+        assert!(!urls.is_empty(), "endpoint pool must have at least one URL");
+        let endpoints = urls
+            .into_iter()
+            .map(|url| EndpointState {
+                url,
+                ewma_latency: None,
+                consecutive_failures: 0,
+                cooldown_until: None,
+            })
+            .collect();
+        Self {
+            endpoints: Mutex::new(endpoints),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pick a healthy endpoint, round-robining among those within
+    /// `LATENCY_TOLERANCE` of the fastest observed latency.
+    pub fn best(&self) -> Url {
+        self.best_excluding(None)
+    }
+
+    /// Same as `best`, but prefers to avoid `exclude` as long as another
+    /// healthy endpoint remains; used to fail a retry over to a different
+    /// server than the one that just failed it.
+    pub fn best_excluding(&self, exclude: Option<&Url>) -> Url {
+        let endpoints = self.endpoints.lock().expect("endpoint pool lock poisoned");
+        let now = Instant::now();
+
+        let mut candidates: Vec<&EndpointState> = endpoints
+            .iter()
+            .filter(|e| e.is_healthy(now) && Some(&e.url) != exclude)
+            .collect();
+
+        if candidates.is_empty() {
+            // Every endpoint is either cooling down or excluded: better to
+            // retry against *something* than to fail outright.
+            candidates = endpoints.iter().collect();
+        }
+
+        // Untested endpoints (no EWMA sample yet) are preferred over any
+        // endpoint with a known latency, so load spreads across the whole
+        // pool before we start favoring measured performers.
+        let untested: Vec<&EndpointState> = candidates
+            .iter()
+            .copied()
+            .filter(|e| e.ewma_latency.is_none())
+            .collect();
+
+        let pool = if !untested.is_empty() {
+            untested
+        } else {
+            let best_latency = candidates
+                .iter()
+                .filter_map(|e| e.ewma_latency)
+                .fold(f64::INFINITY, f64::min);
+            candidates
+                .into_iter()
+                .filter(|e| {
+                    e.ewma_latency
+                        .map_or(false, |latency| latency <= best_latency * LATENCY_TOLERANCE)
+                })
+                .collect()
+        };
+
+        let index = self.next.fetch_add(1, AtomicOrdering::Relaxed) % pool.len();
+        pool[index].url.clone()
+    }
+
+    pub fn record_success(&self, url: &Url, latency: Duration) {
+        let mut endpoints = self.endpoints.lock().expect("endpoint pool lock poisoned");
+        if let Some(endpoint) = endpoints.iter_mut().find(|e| &e.url == url) {
+            endpoint.consecutive_failures = 0;
+            endpoint.cooldown_until = None;
+            let sample = latency.as_secs_f64();
+            endpoint.ewma_latency = Some(match endpoint.ewma_latency {
+                Some(prev) => EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * prev,
+                None => sample,
+            });
+        }
+    }
+
+    pub fn record_failure(&self, url: &Url) {
+        let mut endpoints = self.endpoints.lock().expect("endpoint pool lock poisoned");
+        if let Some(endpoint) = endpoints.iter_mut().find(|e| &e.url == url) {
+            endpoint.consecutive_failures += 1;
+            if endpoint.consecutive_failures >= FAILURE_THRESHOLD {
+                endpoint.cooldown_until = Some(Instant::now() + COOLDOWN);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn urls(n: usize) -> Vec<Url> {
+        (0..n)
+            .map(|i| Url::parse(&format!("https://endpoint{}.example.com", i)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn untested_endpoints_are_preferred_and_round_robined() {
+        let pool = EndpointPool::new(urls(3));
+
+        // No EWMA samples yet: every `best()` call should round-robin
+        // across all three rather than repeating one.
+        let mut seen = HashSet::new();
+        for _ in 0..3 {
+            seen.insert(pool.best().to_string());
+        }
+        assert_eq!(seen.len(), 3);
+    }
+
+    #[test]
+    fn near_best_endpoints_share_load_instead_of_pinning_to_the_min() {
+        let endpoints = urls(3);
+        let pool = EndpointPool::new(endpoints.clone());
+
+        // Give every endpoint a sample so `best()` moves past the
+        // "untested" branch: endpoint 0 is fastest, 1 is close behind
+        // (within LATENCY_TOLERANCE), 2 is far slower.
+        pool.record_success(&endpoints[0], Duration::from_millis(100));
+        pool.record_success(&endpoints[1], Duration::from_millis(110));
+        pool.record_success(&endpoints[2], Duration::from_millis(500));
+
+        let mut seen = HashSet::new();
+        for _ in 0..10 {
+            seen.insert(pool.best().to_string());
+        }
+
+        assert!(seen.contains(&endpoints[0].to_string()));
+        assert!(seen.contains(&endpoints[1].to_string()));
+        assert!(
+            !seen.contains(&endpoints[2].to_string()),
+            "the far-slower endpoint should not have been selected"
+        );
+    }
+
+    #[test]
+    fn best_excluding_avoids_the_given_endpoint_when_another_is_healthy() {
+        let endpoints = urls(2);
+        let pool = EndpointPool::new(endpoints.clone());
+
+        for _ in 0..10 {
+            let picked = pool.best_excluding(Some(&endpoints[0]));
+            assert_eq!(picked, endpoints[1]);
+        }
+    }
+
+    #[test]
+    fn cooling_down_endpoint_is_skipped_while_another_is_healthy() {
+        let endpoints = urls(2);
+        let pool = EndpointPool::new(endpoints.clone());
+
+        for _ in 0..3 {
+            pool.record_failure(&endpoints[0]);
+        }
+
+        for _ in 0..10 {
+            assert_eq!(pool.best(), endpoints[1]);
+        }
+    }
+
+    #[test]
+    fn every_endpoint_unhealthy_still_returns_something_rather_than_panicking() {
+        let endpoints = urls(2);
+        let pool = EndpointPool::new(endpoints.clone());
+
+        for url in &endpoints {
+            for _ in 0..3 {
+                pool.record_failure(url);
+            }
+        }
+
+        // All endpoints are cooling down: `best()` should still hand back
+        // one of them instead of panicking on an empty pool.
+        let picked = pool.best();
+        assert!(endpoints.contains(&picked));
+    }
+}
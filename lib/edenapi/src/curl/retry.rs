@@ -0,0 +1,283 @@
+// Copyright Facebook, Inc. 2019
+
+//! Support for retrying individual transfers within a `Multi` batch with
+//! exponential backoff, instead of `multi_request` surfacing the first
+//! retryable failure as an error for the whole batch.
+//!
+//! Only idempotent requests are eligible for this; every request this
+//! crate issues carries a CBOR payload that is safe to resend unchanged,
+//! so no request-specific opt-in is needed.
+
+use std::cmp;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+use curl::easy::{Handler, WriteError};
+use rand::Rng;
+use url::Url;
+
+use crate::progress::ProgressHandle;
+
+/// HTTP status codes that indicate a transient, retryable condition.
+pub fn is_retryable_status(code: u32) -> bool {
+    matches!(code, 429 | 500 | 502 | 503 | 504)
+}
+
+/// Curl (transport-level) errors that are worth retrying rather than
+/// surfacing immediately.
+pub fn is_retryable_curl_error(err: &curl::Error) -> bool {
+    err.is_couldnt_connect()
+        || err.is_operation_timedout()
+        || err.is_recv_error()
+        || err.is_send_error()
+}
+
+/// Governs how many times a handle may be retried and how long to wait
+/// between attempts.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 4,
+            base_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter: `base * 2^(attempt - 1)`, capped at
+    /// `max_backoff`, plus up to 50% extra random jitter so that a batch of
+    /// handles that fail at the same moment don't all retry in lockstep.
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let shift = cmp::min(attempt.saturating_sub(1), 20) as u32;
+        let exp_ms = (self.base_backoff.as_millis() as u64).saturating_mul(1u64 << shift);
+        let capped_ms = cmp::min(exp_ms, self.max_backoff.as_millis() as u64);
+        let jitter_ms = rand::thread_rng().gen_range(0, capped_ms / 2 + 1);
+        Duration::from_millis(capped_ms + jitter_ms)
+    }
+}
+
+/// A `curl::easy::Handler` that, in addition to collecting the response
+/// body and reporting progress like `handler::Collector`, also tracks how
+/// many times this handle has been resubmitted, which endpoint it is
+/// currently targeting, and captures response headers so a `Retry-After`
+/// value can be honored.
+pub struct RetryCollector {
+    data: Vec<u8>,
+    headers: Vec<String>,
+    progress: Option<ProgressHandle>,
+    attempt: usize,
+    endpoint: Url,
+    started_at: Instant,
+}
+
+impl RetryCollector {
+    pub fn new(endpoint: Url, progress: Option<ProgressHandle>) -> Self {
+        Self {
+            data: Vec::new(),
+            headers: Vec::new(),
+            progress,
+            attempt: 0,
+            endpoint,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn attempt(&self) -> usize {
+        self.attempt
+    }
+
+    pub fn endpoint(&self) -> &Url {
+        &self.endpoint
+    }
+
+    /// Time since this handle was created or last reset for a retry; for a
+    /// retried handle this includes the backoff wait before it was re-armed.
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Prepare this handle to be re-armed on the `Multi`, possibly against a
+    /// different endpoint: clear out the previous response (the POST body
+    /// itself is untouched, since it was already copied into the handle by
+    /// `post_fields_copy`), bump the attempt counter, and restart the
+    /// latency clock.
+    pub fn reset_for_retry(&mut self, endpoint: Url) {
+        self.data.clear();
+        self.headers.clear();
+        self.attempt += 1;
+        self.endpoint = endpoint;
+        self.started_at = Instant::now();
+    }
+
+    /// Parse the `Retry-After` header, if the last response included one,
+    /// as a number of seconds.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.headers.iter().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.trim().eq_ignore_ascii_case("retry-after") {
+                value.trim().parse::<u64>().ok().map(Duration::from_secs)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl Handler for RetryCollector {
+    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        self.data.extend_from_slice(data);
+        if let Some(ref mut progress) = self.progress {
+            progress.set_downloaded(self.data.len() as u64);
+        }
+        Ok(data.len())
+    }
+
+    fn header(&mut self, data: &[u8]) -> bool {
+        if let Ok(line) = std::str::from_utf8(data) {
+            self.headers.push(line.trim_end().to_string());
+        }
+        true
+    }
+}
+
+/// A handle that failed with a retryable condition and is waiting to become
+/// eligible for resubmission.
+pub struct PendingRetry<H> {
+    pub ready_at: Instant,
+    pub handle: curl::easy::Easy2<H>,
+}
+
+impl<H> PendingRetry<H> {
+    pub fn new(handle: curl::easy::Easy2<H>, delay: Duration) -> Self {
+        Self {
+            ready_at: Instant::now() + delay,
+            handle,
+        }
+    }
+}
+
+impl<H> PartialEq for PendingRetry<H> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ready_at == other.ready_at
+    }
+}
+impl<H> Eq for PendingRetry<H> {}
+impl<H> PartialOrd for PendingRetry<H> {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<H> Ord for PendingRetry<H> {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        // Reversed so that a `BinaryHeap` (a max-heap by default) pops the
+        // *soonest* eligible retry first, giving us a min-heap keyed on
+        // `ready_at`.
+        other.ready_at.cmp(&self.ready_at)
+    }
+}
+
+/// A min-heap of handles waiting to be retried, ordered by the instant at
+/// which each becomes eligible for resubmission.
+pub type RetryQueue<H> = BinaryHeap<PendingRetry<H>>;
+
+/// Block until the earliest pending retry in `queue` is eligible to be
+/// re-armed, if any are queued at all.
+pub fn sleep_until_next_retry<H>(queue: &RetryQueue<H>) {
+    if let Some(next) = queue.peek() {
+        let now = Instant::now();
+        if next.ready_at > now {
+            std::thread::sleep(next.ready_at - now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct NoopHandler;
+    impl Handler for NoopHandler {}
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 4,
+            base_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_each_attempt_until_capped() {
+        let policy = policy();
+
+        // With up to 50% jitter, attempt N's backoff should fall in
+        // [base * 2^(N-1), base * 2^(N-1) * 1.5].
+        for attempt in 1..=4 {
+            let base = policy.base_backoff.as_millis() as u64 * (1 << (attempt - 1));
+            let backoff = policy.backoff(attempt).as_millis() as u64;
+            assert!(
+                backoff >= base && backoff <= base + base / 2,
+                "attempt {}: expected backoff in [{}, {}], got {}",
+                attempt,
+                base,
+                base + base / 2,
+                backoff
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_never_exceeds_max_plus_jitter() {
+        let policy = policy();
+        let max_ms = policy.max_backoff.as_millis() as u64;
+
+        for attempt in 1..=50 {
+            let backoff = policy.backoff(attempt).as_millis() as u64;
+            assert!(
+                backoff <= max_ms + max_ms / 2,
+                "attempt {}: backoff {} exceeded max {} plus jitter",
+                attempt,
+                backoff,
+                max_ms
+            );
+        }
+    }
+
+    #[test]
+    fn pending_retry_min_heap_pops_soonest_first() {
+        let mut queue: RetryQueue<NoopHandler> = BinaryHeap::new();
+        queue.push(PendingRetry::new(
+            curl::easy::Easy2::new(NoopHandler),
+            Duration::from_secs(5),
+        ));
+        queue.push(PendingRetry::new(
+            curl::easy::Easy2::new(NoopHandler),
+            Duration::from_millis(1),
+        ));
+        queue.push(PendingRetry::new(
+            curl::easy::Easy2::new(NoopHandler),
+            Duration::from_secs(1),
+        ));
+
+        let mut popped = Vec::new();
+        while let Some(pending) = queue.pop() {
+            popped.push(pending.ready_at);
+        }
+
+        let mut sorted = popped.clone();
+        sorted.sort();
+        assert_eq!(popped, sorted, "heap did not pop in ready_at order");
+    }
+}